@@ -1,14 +1,16 @@
-use automerge_repo::{ConnDirection, DocHandle, DocumentId, Repo, Storage};
+use automerge_repo::{ConnDirection, DocHandle, DocumentId, Repo, RepoId, Storage};
 use autosurgeon::{hydrate, reconcile, Hydrate, Reconcile};
 use axum::extract::State;
 use axum::routing::get;
 use axum::{Json, Router};
 use clap::Parser;
-use futures::FutureExt;
+use futures::{FutureExt, StreamExt};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Handle;
+use tokio::sync::Notify;
 use tokio::time::{sleep, Duration};
 
 #[derive(Parser, Debug)]
@@ -26,9 +28,33 @@ struct Args {
     customer_id: String,
 }
 
+/// An ack broadcast over `DocHandle::broadcast_ephemeral`, tagged with its
+/// sender's `RepoId` by the ephemeral transport itself. Kept off the
+/// document so the bakery algorithm's liveness chatter doesn't bloat its
+/// history -- the tradeoff is that these acks aren't replayed for a peer
+/// that reconnects or joins late, unlike `number`/`output` which live in
+/// the document proper.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum AckMessage {
+    /// The sender's current view of every customer's bakery number.
+    Number(HashMap<String, u32>),
+    /// The latest `output` the sender has seen.
+    Output(u32),
+}
+
+/// Every customer's last-broadcast view of everyone's numbers, keyed by the
+/// customer who broadcast it.
+type NumberAcks = Arc<Mutex<HashMap<String, HashMap<String, u32>>>>;
+/// The latest output each customer has acknowledged, keyed by that customer.
+type OutputAcks = Arc<Mutex<HashMap<String, u32>>>;
+
 struct AppState {
     doc_handle: DocHandle,
     customer_id: String,
+    customers: Vec<String>,
+    acks_of_numbers: NumberAcks,
+    acks_of_output: OutputAcks,
+    ack_notify: Arc<Notify>,
 }
 
 async fn get_doc_id(State(state): State<Arc<AppState>>) -> Json<DocumentId> {
@@ -37,27 +63,58 @@ async fn get_doc_id(State(state): State<Arc<AppState>>) -> Json<DocumentId> {
 
 async fn increment(State(state): State<Arc<AppState>>) -> Json<u32> {
     // Enter the critical section.
-    run_bakery_algorithm(&state.doc_handle, &state.customer_id).await;
+    run_bakery_algorithm(
+        &state.doc_handle,
+        &state.customer_id,
+        &state.acks_of_numbers,
+        &state.ack_notify,
+    )
+    .await;
     println!("Entered critical section.");
 
     // Increment the output
-    let output = increment_output(&state.doc_handle, &state.customer_id).await;
+    let output = increment_output(
+        &state.doc_handle,
+        &state.customer_id,
+        &state.customers,
+        &state.acks_of_output,
+        &state.ack_notify,
+    )
+    .await;
     println!("Incremented output to {:?}.", output);
 
     // Exit the critical section.
-    start_outside_the_bakery(&state.doc_handle, &state.customer_id).await;
+    start_outside_the_bakery(
+        &state.doc_handle,
+        &state.customer_id,
+        &state.acks_of_numbers,
+        &state.ack_notify,
+    )
+    .await;
     println!("Exited critical section.");
 
     Json(output)
 }
 
-async fn increment_output(doc_handle: &DocHandle, customer_id: &str) -> u32 {
+/// Waits on either the document advancing or a new ack arriving -- whichever
+/// of the two a waiting loop's condition actually depends on.
+async fn wait_for_doc_or_ack(doc_handle: &DocHandle, ack_notify: &Notify) -> bool {
+    tokio::select! {
+        result = doc_handle.changed_with_heads() => result.is_ok(),
+        _ = ack_notify.notified() => true,
+    }
+}
+
+async fn increment_output(
+    doc_handle: &DocHandle,
+    customer_id: &str,
+    customers: &[String],
+    acks_of_output: &OutputAcks,
+    ack_notify: &Notify,
+) -> u32 {
     let latest = doc_handle.with_doc_mut(|doc| {
         let mut bakery: Bakery = hydrate(doc).unwrap();
         bakery.output += 1;
-        bakery
-            .output_seen
-            .insert(customer_id.to_string(), bakery.output);
         let mut tx = doc.transaction();
         reconcile(&mut tx, &bakery).unwrap();
         tx.commit();
@@ -65,23 +122,17 @@ async fn increment_output(doc_handle: &DocHandle, customer_id: &str) -> u32 {
     });
     // Wait for all peers to have acknowlegded the new output.
     loop {
-        if doc_handle.changed().await.is_err() {
+        if !wait_for_doc_or_ack(doc_handle, ack_notify).await {
             // Shutdown.
             break;
         }
-        let acked_by_all = doc_handle.with_doc(|doc| {
-            let bakery: Bakery = hydrate(doc).unwrap();
-            bakery.output_seen.values().fold(
-                true,
-                |acc, output| {
-                    if !acc {
-                        acc
-                    } else {
-                        output == &latest
-                    }
-                },
-            )
-        });
+        let acked_by_all = {
+            let acks = acks_of_output.lock().unwrap();
+            customers
+                .iter()
+                .filter(|id| id.as_str() != customer_id)
+                .all(|id| acks.get(id) == Some(&latest))
+        };
         if acked_by_all {
             break;
         }
@@ -89,21 +140,18 @@ async fn increment_output(doc_handle: &DocHandle, customer_id: &str) -> u32 {
     latest
 }
 
-async fn run_bakery_algorithm(doc_handle: &DocHandle, customer_id: &String) {
+async fn run_bakery_algorithm(
+    doc_handle: &DocHandle,
+    customer_id: &str,
+    acks_of_numbers: &NumberAcks,
+    ack_notify: &Notify,
+) {
     let our_number = doc_handle.with_doc_mut(|doc| {
         // Pick a number that is higher than all others.
         let mut bakery: Bakery = hydrate(doc).unwrap();
-        let customers_with_number = bakery
-            .customers
-            .clone()
-            .iter()
-            .map(|(id, c)| (id.clone(), c.number))
-            .collect();
         let highest_number = bakery.customers.values().map(|c| c.number).max().unwrap();
         let our_number = highest_number + 1;
-        let our_info = bakery.customers.get_mut(customer_id).unwrap();
-        our_info.views_of_others = customers_with_number;
-        our_info.number = our_number;
+        bakery.customers.get_mut(customer_id).unwrap().number = our_number;
         let mut tx = doc.transaction();
         reconcile(&mut tx, &bakery).unwrap();
         tx.commit();
@@ -111,73 +159,121 @@ async fn run_bakery_algorithm(doc_handle: &DocHandle, customer_id: &String) {
     });
 
     loop {
-        if doc_handle.changed().await.is_err() {
+        if !wait_for_doc_or_ack(doc_handle, ack_notify).await {
             // Shutdown.
             break;
         }
-        let entered_cs = doc_handle.with_doc(|doc| {
-            let bakery: Bakery = hydrate(doc).unwrap();
-
-            // Wait for all peers to have acknowlegded our number.
-            let acked_by_all = bakery
-                .customers
-                .iter()
-                .filter(|(id, _)| id != &customer_id)
-                .fold(true, |acc, (_, c)| {
-                    if !acc {
-                        acc
-                    } else {
-                        let view_of_our_number = c.views_of_others.get(customer_id).unwrap();
-                        view_of_our_number == &our_number
-                    }
-                });
-
-            if !acked_by_all {
-                return false;
-            }
+        let entered_cs = {
+            let acks = acks_of_numbers.lock().unwrap();
+            doc_handle.with_doc(|doc| {
+                let bakery: Bakery = hydrate(doc).unwrap();
 
-            // Lowest non-negative number.
-            let has_lower = bakery
-                .customers
-                .iter()
-                .filter_map(|(id, c)| {
-                    if c.number == 0 || id == customer_id {
-                        None
-                    } else {
-                        Some((id, c.number))
-                    }
-                })
-                .min_by_key(|(_, num)| *num);
+                // Wait for all peers to have acknowlegded our number.
+                let acked_by_all = bakery
+                    .customers
+                    .keys()
+                    .filter(|id| id.as_str() != customer_id)
+                    .fold(true, |acc, id| {
+                        if !acc {
+                            acc
+                        } else {
+                            acks.get(id).and_then(|view| view.get(customer_id))
+                                == Some(&our_number)
+                        }
+                    });
+
+                if !acked_by_all {
+                    return false;
+                }
 
-            // Everyone else is at zero.
-            if has_lower.is_none() {
-                return true;
-            }
+                // Lowest non-negative number.
+                let has_lower = bakery
+                    .customers
+                    .iter()
+                    .filter_map(|(id, c)| {
+                        if c.number == 0 || id == customer_id {
+                            None
+                        } else {
+                            Some((id, c.number))
+                        }
+                    })
+                    .min_by_key(|(_, num)| *num);
+
+                // Everyone else is at zero.
+                if has_lower.is_none() {
+                    return true;
+                }
 
-            let (id, lowest_number) = has_lower.unwrap();
+                let (id, lowest_number) = has_lower.unwrap();
 
-            if lowest_number == our_number {
-                // Break tie by customer id.
-                return customer_id < id;
-            }
+                if lowest_number == our_number {
+                    // Break tie by customer id.
+                    return customer_id < id.as_str();
+                }
 
-            lowest_number > our_number
-        });
+                lowest_number > our_number
+            })
+        };
         if entered_cs {
             return;
         }
     }
 }
 
-async fn acknowlegde_changes(doc_handle: DocHandle, customer_id: String) {
-    let (mut our_view, mut output_seen) = doc_handle.with_doc(|doc| {
+/// Broadcasts our current view of everyone's numbers and the latest output
+/// we've seen, so other peers' `run_bakery_algorithm`/`increment_output`
+/// waiting loops can observe our ack.
+fn broadcast_acks(doc_handle: &DocHandle, view: &HashMap<String, u32>, output: u32) {
+    if let Ok(bytes) = bincode::serialize(&AckMessage::Number(view.clone())) {
+        doc_handle.broadcast_ephemeral(bytes);
+    }
+    if let Ok(bytes) = bincode::serialize(&AckMessage::Output(output)) {
+        doc_handle.broadcast_ephemeral(bytes);
+    }
+}
+
+async fn acknowlegde_changes(
+    doc_handle: DocHandle,
+    customer_id: String,
+    acks_of_numbers: NumberAcks,
+    acks_of_output: OutputAcks,
+    ack_notify: Arc<Notify>,
+) {
+    // Consume other peers' acks into the shared maps, in its own task so
+    // this function's own ack-broadcasting loop below isn't starved by a
+    // slow ephemeral stream.
+    let ephemeral_doc_handle = doc_handle.clone();
+    tokio::spawn(async move {
+        let mut ephemeral = Box::pin(ephemeral_doc_handle.ephemeral());
+        while let Some((origin, payload)) = ephemeral.next().await {
+            let Ok(message) = bincode::deserialize::<AckMessage>(&payload) else {
+                continue;
+            };
+            match message {
+                AckMessage::Number(view) => {
+                    acks_of_numbers.lock().unwrap().insert(origin.0, view);
+                }
+                AckMessage::Output(output) => {
+                    acks_of_output.lock().unwrap().insert(origin.0, output);
+                }
+            }
+            ack_notify.notify_waiters();
+        }
+    });
+
+    let (mut our_view, mut our_output) = doc_handle.with_doc(|doc| {
         let bakery: Bakery = hydrate(doc).unwrap();
-        let our_info = bakery.customers.get(&customer_id).unwrap();
-        let output_seen = bakery.output_seen.get(&customer_id).unwrap();
-        (our_info.views_of_others.clone(), *output_seen)
+        let view = bakery
+            .customers
+            .iter()
+            .map(|(id, c)| (id.clone(), c.number))
+            .collect();
+        (view, bakery.output)
     });
+    broadcast_acks(&doc_handle, &our_view, our_output);
+
     loop {
-        if doc_handle.changed().await.is_err() {
+        if doc_handle.changed_with_heads().await.is_err() {
             // Shutdown.
             break;
         }
@@ -192,41 +288,25 @@ async fn acknowlegde_changes(doc_handle: DocHandle, customer_id: String) {
                 (numbers, bakery.output)
             });
 
-        // Only change the doc if something needs acknowledgement.
-        if customers_with_number == our_view && output_seen == new_output {
+        // Only re-broadcast if our view actually moved.
+        if customers_with_number == our_view && new_output == our_output {
             continue;
         }
-
-        (our_view, output_seen) = doc_handle.with_doc_mut(|doc| {
-            let mut bakery: Bakery = hydrate(doc).unwrap();
-            let customers_with_number: HashMap<String, u32> = bakery
-                .customers
-                .clone()
-                .iter()
-                .map(|(id, c)| (id.clone(), c.number))
-                .collect();
-            let our_info = bakery.customers.get_mut(&customer_id).unwrap();
-            // Ack changes made by others.
-            our_info.views_of_others = customers_with_number.clone();
-
-            // Ack any new output.
-            bakery
-                .output_seen
-                .insert(customer_id.clone(), bakery.output);
-
-            let mut tx = doc.transaction();
-            reconcile(&mut tx, &bakery).unwrap();
-            tx.commit();
-            (customers_with_number, bakery.output)
-        });
+        our_view = customers_with_number;
+        our_output = new_output;
+        broadcast_acks(&doc_handle, &our_view, our_output);
     }
 }
 
-async fn start_outside_the_bakery(doc_handle: &DocHandle, customer_id: &String) {
+async fn start_outside_the_bakery(
+    doc_handle: &DocHandle,
+    customer_id: &str,
+    acks_of_numbers: &NumberAcks,
+    ack_notify: &Notify,
+) {
     doc_handle.with_doc_mut(|doc| {
         let mut bakery: Bakery = hydrate(doc).unwrap();
-        let our_info = bakery.customers.get_mut(customer_id).unwrap();
-        our_info.number = 0;
+        bakery.customers.get_mut(customer_id).unwrap().number = 0;
         let mut tx = doc.transaction();
         reconcile(&mut tx, &bakery).unwrap();
         tx.commit();
@@ -234,21 +314,27 @@ async fn start_outside_the_bakery(doc_handle: &DocHandle, customer_id: &String)
 
     // Wait for acks from peers.
     loop {
-        if doc_handle.changed().await.is_err() {
+        if !wait_for_doc_or_ack(doc_handle, ack_notify).await {
             // Shutdown.
             break;
         }
-        let synced = doc_handle.with_doc(|doc| {
-            let bakery: Bakery = hydrate(doc).unwrap();
-            bakery.customers.iter().fold(true, |acc, (_, c)| {
-                if !acc {
-                    acc
-                } else {
-                    let view_of_our_number = c.views_of_others.get(customer_id).unwrap();
-                    view_of_our_number == &0
-                }
+        let synced = {
+            let acks = acks_of_numbers.lock().unwrap();
+            doc_handle.with_doc(|doc| {
+                let bakery: Bakery = hydrate(doc).unwrap();
+                bakery
+                    .customers
+                    .keys()
+                    .filter(|id| id.as_str() != customer_id)
+                    .fold(true, |acc, id| {
+                        if !acc {
+                            acc
+                        } else {
+                            acks.get(id).and_then(|view| view.get(customer_id)) == Some(&0)
+                        }
+                    })
             })
-        });
+        };
         if synced {
             break;
         }
@@ -283,14 +369,12 @@ async fn request_increment(doc_handle: DocHandle, customer_id: String, customers
 #[derive(Debug, Clone, Reconcile, Hydrate, PartialEq)]
 struct Customer {
     pub number: u32,
-    pub views_of_others: HashMap<String, u32>,
 }
 
 #[derive(Default, Debug, Clone, Reconcile, Hydrate, PartialEq)]
 struct Bakery {
     pub customers: HashMap<String, Customer>,
     pub output: u32,
-    pub output_seen: HashMap<String, u32>,
 }
 
 struct NoStorage;
@@ -311,8 +395,10 @@ async fn main() {
         .map(|id| id.to_string())
         .collect();
 
-    // Create a repo.
-    let repo = Repo::new(None, Box::new(NoStorage));
+    // Create a repo. The local repo id doubles as this customer's identity
+    // in the ephemeral ack protocol, so other peers can key their acks by
+    // it directly.
+    let repo = Repo::new(Some(RepoId(args.customer_id.clone())), Box::new(NoStorage));
     let repo_handle = repo.run();
 
     let handle = Handle::current();
@@ -346,14 +432,8 @@ async fn main() {
                 // so that peers block on acks
                 // until all others are up and running.
                 number: u32::MAX,
-                views_of_others: customers
-                    .clone()
-                    .into_iter()
-                    .map(|id| (id, u32::MAX))
-                    .collect(),
             };
             bakery.customers.insert(customer_id.to_string(), customer);
-            bakery.output_seen.insert(customer_id.to_string(), 0);
         }
 
         // Create the initial document.
@@ -389,18 +469,34 @@ async fn main() {
         repo_handle.request_document(doc_id).await.unwrap()
     };
 
+    let acks_of_numbers: NumberAcks = Arc::new(Mutex::new(HashMap::new()));
+    let acks_of_output: OutputAcks = Arc::new(Mutex::new(HashMap::new()));
+    let ack_notify = Arc::new(Notify::new());
+
     let app_state = Arc::new(AppState {
         doc_handle: doc_handle.clone(),
         customer_id: args.customer_id.clone(),
+        customers: customers.clone(),
+        acks_of_numbers: acks_of_numbers.clone(),
+        acks_of_output: acks_of_output.clone(),
+        ack_notify: ack_notify.clone(),
     });
 
     // Do this in a task, so that the server immediatly starts running.
     let customer_id = args.customer_id.clone();
     let doc_handle_clone = doc_handle.clone();
+    let startup_acks_of_numbers = acks_of_numbers.clone();
+    let startup_ack_notify = ack_notify.clone();
     handle.spawn(async move {
         // Start the algorithm "outside the bakery".
         // The acks makes this wait for all others to be up and running.
-        start_outside_the_bakery(&doc_handle_clone, &customer_id).await;
+        start_outside_the_bakery(
+            &doc_handle_clone,
+            &customer_id,
+            &startup_acks_of_numbers,
+            &startup_ack_notify,
+        )
+        .await;
 
         // Continuously requests a new increment.
         request_increment(doc_handle_clone, customer_id, customers).await;
@@ -408,7 +504,14 @@ async fn main() {
 
     // A task that continuously acknowledges changes made by others.
     handle.spawn(async move {
-        acknowlegde_changes(doc_handle, args.customer_id.clone()).await;
+        acknowlegde_changes(
+            doc_handle,
+            args.customer_id.clone(),
+            acks_of_numbers,
+            acks_of_output,
+            ack_notify,
+        )
+        .await;
     });
 
     let app = Router::new()
@@ -419,12 +522,9 @@ async fn main() {
     tokio::select! {
         _ = serve.fuse() => {},
         _ = tokio::signal::ctrl_c().fuse() => {
-            Handle::current()
-                .spawn_blocking(|| {
-                    repo_handle.stop().unwrap();
-                })
-                .await
-                .unwrap();
+            // Let in-flight sync traffic and storage writes land instead of
+            // tearing the repo down mid-write.
+            repo_handle.shutdown().await.unwrap();
         }
     }
 }
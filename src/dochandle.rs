@@ -1,10 +1,16 @@
 use crate::interfaces::{DocumentId, RepoId};
-use crate::repo::{new_repo_future_with_resolver, RepoError, RepoEvent, RepoFuture};
+use crate::repo::{
+    new_repo_future_with_resolver, BatchedChangeStream, ChangeNotification, HeadsObserver,
+    RepoError, RepoEvent, RepoFuture,
+};
 use automerge::Automerge;
 use crossbeam_channel::Sender;
+use futures::Stream;
 use parking_lot::RwLock;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
 
 /// A wrapper around a document shared between a handle and the repo.
 #[derive(Clone, Debug)]
@@ -41,10 +47,9 @@ impl Clone for DocHandle {
 
 impl Drop for DocHandle {
     fn drop(&mut self) {
-        // Close the document when the last handle drops.
-        // TODO: turn this into a `delete` concept,
-        // based on an explicit method call(not drop),
-        // which would clear storage as well?
+        // Close the document when the last handle drops. This only stops
+        // the repo from keeping the document in memory; it does not purge
+        // storage or tell peers to forget it. For that, call `delete`.
         if self.handle_count.fetch_sub(1, Ordering::SeqCst) == 0 {
             self.repo_sender
                 .send(RepoEvent::DocClosed(self.document_id.clone()))
@@ -109,10 +114,114 @@ impl DocHandle {
         res
     }
 
+    /// Like `with_doc_mut`, but never blocks: if the write lock is held by
+    /// another handle, returns `None` immediately instead of stalling the
+    /// calling task. Useful in tight `changed().await` loops, where blocking
+    /// here would stall the executor thread along with it.
+    pub fn try_with_doc_mut<F, T>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut Automerge) -> T,
+    {
+        let res = {
+            let mut state = self.shared_document.try_write()?;
+            f(&mut state.automerge)
+        };
+        self.repo_sender
+            .send(RepoEvent::DocChange(self.document_id.clone()))
+            .expect("Failed to send doc change event.");
+        Some(res)
+    }
+
+    /// Like `with_doc`, but never blocks: if the write lock is held by
+    /// another handle, returns `None` immediately instead of stalling the
+    /// calling task.
+    pub fn try_with_doc<F, T>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&Automerge) -> T,
+    {
+        let state = self.shared_document.try_read()?;
+        Some(f(&state.automerge))
+    }
+
+    /// Deletes the document: storage is purged of its chunks, and peers are
+    /// told to stop re-announcing and purge their own copies. Safe to call
+    /// more than once (from this handle or a clone held elsewhere) -- only
+    /// the first call has any effect.
+    pub fn delete(self) {
+        self.repo_sender
+            .send(RepoEvent::DocDeleted(self.document_id.clone()))
+            .expect("Failed to send doc delete event.");
+    }
+
+    /// Sends `bytes` to every connected peer, tagged with this document's id,
+    /// without applying it to the document or persisting it to storage.
+    /// There's no per-document subscription gating at the connection level
+    /// yet -- it goes out over every connection regardless of whether that
+    /// peer has ever touched this document, and it's on the receiving side
+    /// to ignore documents it doesn't care about via `ephemeral()`. For
+    /// transient coordination data (presence, acks) that shouldn't bloat
+    /// document history.
+    pub fn broadcast_ephemeral(&self, bytes: Vec<u8>) {
+        self.repo_sender
+            .send(RepoEvent::Ephemeral(self.document_id.clone(), bytes))
+            .expect("Failed to send ephemeral message.");
+    }
+
+    /// A stream of ephemeral messages broadcast by other peers for this
+    /// document, each tagged with the `RepoId` that originated it.
+    pub fn ephemeral(&self) -> impl Stream<Item = (RepoId, Vec<u8>)> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.repo_sender
+            .send(RepoEvent::SubscribeEphemeral(
+                self.document_id.clone(),
+                sender,
+            ))
+            .expect("Failed to subscribe to ephemeral messages.");
+        UnboundedReceiverStream::new(receiver)
+    }
+
+    /// Like `changed`, but only resolves once the document's heads have
+    /// actually advanced (applying a sync message that carried no new
+    /// changes won't wake this), and carries the before/after heads and the
+    /// `Patch`es the advance produced.
+    pub fn changed_with_heads(&self) -> RepoFuture<Result<ChangeNotification, RepoError>> {
+        let before = self.with_doc(|doc| doc.get_heads());
+        let (fut, observer) = new_repo_future_with_resolver();
+        self.repo_sender
+            .send(RepoEvent::AddHeadsObserver(
+                self.document_id.clone(),
+                before,
+                HeadsObserver::Once(observer),
+            ))
+            .expect("Failed to send heads observer.");
+        fut
+    }
+
+    /// Like `changed_with_heads`, but keeps listening instead of resolving
+    /// once: each poll drains and merges any notifications that piled up
+    /// since the last poll into one, so a slow consumer doesn't get woken
+    /// once per intermediate change.
+    pub fn changed_with_heads_batched(
+        &self,
+    ) -> impl futures::Stream<Item = Result<ChangeNotification, RepoError>> {
+        let before = self.with_doc(|doc| doc.get_heads());
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.repo_sender
+            .send(RepoEvent::AddHeadsObserver(
+                self.document_id.clone(),
+                before,
+                HeadsObserver::Batched(sender),
+            ))
+            .expect("Failed to send heads observer.");
+        BatchedChangeStream::new(receiver)
+    }
+
     /// Returns a future that will resolve when the document has changed,
-    /// either via another handle, or by applying a sync messsage.
-    /// TODO: check sync message and docs following mutable calls,
-    /// and only resolve the future when there was an actual change.
+    /// either via another handle, or by applying a sync message. This
+    /// resolves on every local mutation, even ones that end up being no-ops
+    /// (e.g. setting a value to what it already was) -- if you need to wait
+    /// for an actual advance of the document's heads, use
+    /// `changed_with_heads` instead.
     pub fn changed(&self) -> RepoFuture<Result<(), RepoError>> {
         let (fut, observer) = new_repo_future_with_resolver();
         self.repo_sender
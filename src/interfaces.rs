@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use thiserror::Error;
+
+/// Uniquely identifies a document across all repos.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DocumentId(pub(crate) [u8; 16]);
+
+impl DocumentId {
+    pub(crate) fn new() -> Self {
+        DocumentId(rand::random())
+    }
+}
+
+impl fmt::Display for DocumentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Uniquely identifies a repo taking part in a sync session.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RepoId(pub String);
+
+impl fmt::Display for RepoId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Errors surfaced to callers waiting on a `RepoFuture`.
+#[derive(Debug, Clone, Error)]
+pub enum RepoError {
+    #[error("the repo has shut down")]
+    Shutdown,
+    #[error("no such document: {0}")]
+    DocumentNotFound(DocumentId),
+    #[error("network error: {0}")]
+    Network(#[from] NetworkError),
+    #[error("storage error: {0}")]
+    Storage(String),
+}
+
+/// Errors that can occur on the wire, independent of the transport used.
+#[derive(Debug, Clone, Error)]
+pub enum NetworkError {
+    #[error("peer disconnected")]
+    Disconnected,
+    #[error("handshake failed: {0}")]
+    HandshakeFailed(String),
+    #[error("peer presented an unexpected identity: expected {expected}, got {got}")]
+    PeerIdMismatch { expected: RepoId, got: RepoId },
+}
+
+/// Adapter implemented by callers who want documents persisted somewhere
+/// durable. All methods have a no-op default so that `Storage` can be
+/// implemented for things like an in-memory or "don't bother" backend with
+/// an empty `impl` block.
+#[async_trait::async_trait]
+pub trait Storage: Send + Sync + 'static {
+    async fn get(&self, _id: DocumentId) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(None)
+    }
+
+    async fn list_all(&self) -> Result<Vec<DocumentId>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    async fn append(&self, _id: DocumentId, _changes: Vec<u8>) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    async fn compact(&self, _id: DocumentId, _full_doc: Vec<u8>) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Remove every chunk persisted for `id`. Called once a document has
+    /// been deleted and should no longer be recoverable from storage.
+    async fn remove(&self, _id: DocumentId) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum StorageError {
+    #[error("{0}")]
+    Other(String),
+}
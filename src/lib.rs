@@ -0,0 +1,10 @@
+mod dochandle;
+mod interfaces;
+mod repo;
+mod tls;
+
+pub use dochandle::DocHandle;
+pub use interfaces::{DocumentId, NetworkError, RepoError, RepoId, Storage, StorageError};
+pub use repo::{ChangeNotification, ConnDirection, Repo, RepoHandle};
+pub(crate) use repo::{new_repo_future_with_resolver, RepoEvent, RepoFuture};
+pub use tls::TlsConfig;
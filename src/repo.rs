@@ -0,0 +1,1316 @@
+use crate::dochandle::{DocHandle, SharedDocument};
+use crate::interfaces::{DocumentId, NetworkError, RepoId, Storage, StorageError};
+pub(crate) use crate::interfaces::RepoError;
+use crate::tls::TlsConfig;
+use automerge::{Automerge, ChangeHash, Patch};
+use crossbeam_channel::{unbounded, Sender};
+use futures::channel::oneshot;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// Which side of a connection we are, mirroring who dialed whom.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnDirection {
+    Incoming,
+    Outgoing,
+}
+
+/// A boxable `AsyncRead + AsyncWrite`, so the repo can hand raw TCP and
+/// TLS-wrapped streams to the same connection-handling code.
+pub(crate) trait AsyncIo: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncIo for T {}
+
+/// One encoded [`WireMessage`] in, one out. Both the raw-TCP and WebSocket
+/// connect paths end up here so the rest of the repo only ever deals with
+/// whole messages, never transport framing.
+pub(crate) type FrameStream = Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>;
+pub(crate) type FrameSink = Pin<Box<dyn Sink<Vec<u8>, Error = NetworkError> + Send>>;
+
+/// Adapts a raw byte stream into a [`FrameStream`]/[`FrameSink`] pair using
+/// length-delimited framing.
+fn framed_from_io(io: Box<dyn AsyncIo>) -> (FrameStream, FrameSink) {
+    let (sink, stream) = Framed::new(io, LengthDelimitedCodec::new()).split();
+    let stream = stream.filter_map(|frame| async move { frame.ok().map(|bytes| bytes.to_vec()) });
+    let sink = sink
+        .with(|bytes: Vec<u8>| futures::future::ready(Ok::<_, std::io::Error>(bytes.into())))
+        .sink_map_err(|e: std::io::Error| NetworkError::HandshakeFailed(e.to_string()));
+    (Box::pin(stream), Box::pin(sink))
+}
+
+/// Adapts a tungstenite WebSocket into the same [`FrameStream`]/[`FrameSink`]
+/// pair, with each sync message carried as one binary frame.
+fn framed_from_websocket<S>(ws_stream: WebSocketStream<S>) -> (FrameStream, FrameSink)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sink, stream) = ws_stream.split();
+    let stream = stream.filter_map(|msg| async move {
+        match msg {
+            Ok(Message::Binary(bytes)) => Some(bytes),
+            _ => None,
+        }
+    });
+    let sink = sink
+        .with(|bytes: Vec<u8>| futures::future::ready(Ok(Message::Binary(bytes))))
+        .sink_map_err(|e: tokio_tungstenite::tungstenite::Error| {
+            NetworkError::HandshakeFailed(e.to_string())
+        });
+    (Box::pin(stream), Box::pin(sink))
+}
+
+/// How many times an ephemeral message may be re-gossiped after it first
+/// reaches a node, bounding the damage a slow multi-hop topology can do.
+const EPHEMERAL_TTL: u8 = 3;
+
+/// How many recently-seen ephemeral message ids to remember for dedup.
+/// Bounds `seen_ephemeral`'s memory use on a long-running node that
+/// handles a steady stream of presence/ack traffic; once full, the oldest
+/// id is forgotten to make room for the newest.
+const MAX_SEEN_EPHEMERAL: usize = 4096;
+
+/// A presence/ack-style message for a document that is gossiped between
+/// peers but never applied to the document or written to storage.
+/// `(session_id, counter)` is unique per message from a given origin and is
+/// used to detect messages a node has already forwarded, so gossip doesn't
+/// loop.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct EphemeralMessage {
+    document_id: DocumentId,
+    origin: RepoId,
+    session_id: u64,
+    counter: u64,
+    ttl: u8,
+    payload: Vec<u8>,
+}
+
+/// Messages exchanged between connected repos.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum WireMessage {
+    Sync {
+        document_id: DocumentId,
+        message: Vec<u8>,
+    },
+    /// Tells the peer a document has been deleted: stop re-announcing it
+    /// and purge any local copy.
+    Tombstone { document_id: DocumentId },
+    Ephemeral(EphemeralMessage),
+}
+
+/// What changed between two points in a document's history.
+#[derive(Debug, Clone)]
+pub struct ChangeNotification {
+    pub before: Vec<ChangeHash>,
+    pub after: Vec<ChangeHash>,
+    pub patches: Vec<Patch>,
+}
+
+impl ChangeNotification {
+    /// Folds `other`, which happened after `self`, into one notification
+    /// spanning both.
+    fn merge(mut self, other: ChangeNotification) -> Self {
+        self.after = other.after;
+        self.patches.extend(other.patches);
+        self
+    }
+}
+
+/// Where a heads-aware observer wants to hear about the document reaching
+/// new heads: once, or repeatedly until it's dropped.
+pub(crate) enum HeadsObserver {
+    Once(Resolver<Result<ChangeNotification, RepoError>>),
+    Batched(mpsc::UnboundedSender<Result<ChangeNotification, RepoError>>),
+}
+
+/// A stream of [`ChangeNotification`]s that mirrors Solana's `receive_all`:
+/// every poll drains and merges whatever has already queued up, so a
+/// consumer that was slow to poll gets one notification covering several
+/// changes instead of waking once per change.
+pub(crate) struct BatchedChangeStream {
+    receiver: mpsc::UnboundedReceiver<Result<ChangeNotification, RepoError>>,
+}
+
+impl BatchedChangeStream {
+    pub(crate) fn new(
+        receiver: mpsc::UnboundedReceiver<Result<ChangeNotification, RepoError>>,
+    ) -> Self {
+        BatchedChangeStream { receiver }
+    }
+}
+
+impl Stream for BatchedChangeStream {
+    type Item = Result<ChangeNotification, RepoError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let mut merged = match this.receiver.poll_recv(cx) {
+            Poll::Ready(Some(item)) => item,
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => return Poll::Pending,
+        };
+        while let Ok(next) = this.receiver.try_recv() {
+            merged = match (merged, next) {
+                (Ok(a), Ok(b)) => Ok(a.merge(b)),
+                (Err(e), _) => Err(e),
+                (_, Err(e)) => Err(e),
+            };
+        }
+        Poll::Ready(Some(merged))
+    }
+}
+
+/// Resolves a [`RepoFuture`] once the repo's run loop has an answer.
+pub(crate) struct Resolver<T>(Option<oneshot::Sender<T>>);
+
+impl<T> Resolver<T> {
+    pub(crate) fn resolve(&mut self, value: T) {
+        if let Some(sender) = self.0.take() {
+            let _ = sender.send(value);
+        }
+    }
+}
+
+/// A `Result` that knows how to report "the repo shut down before this
+/// resolved", so `RepoFuture` doesn't need to panic when its resolver is
+/// dropped.
+pub(crate) trait OnCancel {
+    fn on_cancel() -> Self;
+}
+
+impl<T> OnCancel for Result<T, RepoError> {
+    fn on_cancel() -> Self {
+        Err(RepoError::Shutdown)
+    }
+}
+
+/// A future resolved by the repo's run loop, rather than by the task
+/// polling it.
+pub struct RepoFuture<T>(oneshot::Receiver<T>);
+
+impl<T: OnCancel> Future for RepoFuture<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|res| res.unwrap_or_else(|_| T::on_cancel()))
+    }
+}
+
+pub(crate) fn new_repo_future_with_resolver<T>() -> (RepoFuture<T>, Resolver<T>) {
+    let (sender, receiver) = oneshot::channel();
+    (RepoFuture(receiver), Resolver(Some(sender)))
+}
+
+pub(crate) enum RepoEvent {
+    NewDoc(DocumentId, Arc<RwLock<SharedDocument>>),
+    DocChange(DocumentId),
+    DocClosed(DocumentId),
+    AddChangeObserver(DocumentId, Resolver<Result<(), RepoError>>),
+    RequestDoc(DocumentId, Resolver<Result<DocHandle, RepoError>>),
+    /// A document was explicitly deleted, locally or by a peer's tombstone.
+    DocDeleted(DocumentId),
+    /// A sync message for `DocumentId` arrived from the peer at this addr.
+    ReceiveSync(String, DocumentId, Vec<u8>),
+    /// A local call to `changed_with_heads`/`changed_with_heads_batched`,
+    /// registering interest in the document advancing past `before`.
+    AddHeadsObserver(DocumentId, Vec<ChangeHash>, HeadsObserver),
+    /// A local call to `DocHandle::broadcast_ephemeral`.
+    Ephemeral(DocumentId, Vec<u8>),
+    /// An ephemeral message arrived from the connection at this addr.
+    EphemeralReceived(String, EphemeralMessage),
+    /// A local call to `DocHandle::ephemeral`, registering interest in
+    /// ephemeral messages for this document.
+    SubscribeEphemeral(DocumentId, mpsc::UnboundedSender<(RepoId, Vec<u8>)>),
+    ConnectIo(
+        String,
+        FrameStream,
+        FrameSink,
+        ConnDirection,
+        Resolver<Result<(), RepoError>>,
+    ),
+    ConnectionClosed(String),
+    Stop,
+    /// A local call to `RepoHandle::shutdown`.
+    Shutdown(Resolver<Result<(), RepoError>>),
+}
+
+/// Not-yet-running repo. Call [`Repo::run`] to start its background loop
+/// and get back a [`RepoHandle`].
+pub struct Repo {
+    local_repo_id: RepoId,
+    storage: Arc<dyn Storage>,
+    tls_config: Option<TlsConfig>,
+}
+
+impl Repo {
+    pub fn new(local_repo_id: Option<RepoId>, storage: Box<dyn Storage>) -> Self {
+        Repo {
+            local_repo_id: local_repo_id
+                .unwrap_or_else(|| RepoId(format!("{:016x}", rand::random::<u64>()))),
+            storage: Arc::from(storage),
+            tls_config: None,
+        }
+    }
+
+    /// Like [`Repo::new`], but connections made with `connect_tokio_io_tls`
+    /// will use `tls_config` for mutual authentication.
+    pub fn new_with_tls(
+        local_repo_id: Option<RepoId>,
+        storage: Box<dyn Storage>,
+        tls_config: TlsConfig,
+    ) -> Self {
+        Repo {
+            tls_config: Some(tls_config),
+            ..Self::new(local_repo_id, storage)
+        }
+    }
+
+    /// Starts the repo's run loop on a dedicated thread and returns a
+    /// cloneable handle to it.
+    pub fn run(self) -> RepoHandle {
+        let (sender, receiver) = unbounded::<RepoEvent>();
+        let local_repo_id = self.local_repo_id.clone();
+        let tls_config = self.tls_config;
+        let storage = self.storage;
+        let loop_sender = sender.clone();
+        let loop_repo_id = local_repo_id.clone();
+        thread::Builder::new()
+            .name("automerge-repo".to_string())
+            .spawn(move || {
+                let rt = Runtime::new().expect("failed to start the repo's tokio runtime");
+                rt.block_on(run_loop(receiver, loop_sender, loop_repo_id, storage));
+            })
+            .expect("failed to spawn the repo's run thread");
+        RepoHandle {
+            sender,
+            local_repo_id,
+            tls_config,
+        }
+    }
+}
+
+async fn run_loop(
+    receiver: crossbeam_channel::Receiver<RepoEvent>,
+    sender: Sender<RepoEvent>,
+    local_repo_id: RepoId,
+    storage: Arc<dyn Storage>,
+) {
+    let mut documents: HashMap<DocumentId, Arc<RwLock<SharedDocument>>> = HashMap::new();
+    let mut observers: HashMap<DocumentId, Vec<Resolver<Result<(), RepoError>>>> = HashMap::new();
+    // Baseline heads each observer last saw, so we only wake it once the
+    // document has actually advanced past them.
+    let mut heads_observers: HashMap<DocumentId, Vec<(Vec<ChangeHash>, HeadsObserver)>> =
+        HashMap::new();
+    let mut connections: HashMap<String, mpsc::UnboundedSender<Vec<u8>>> = HashMap::new();
+    // Join handles for each connection's background task, so `shutdown` can
+    // wait for queued outbound frames to actually finish writing.
+    let mut connection_tasks: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+    // Join handles for in-flight storage writes, so `shutdown` can wait for
+    // them to land before resolving.
+    let mut pending_storage: Vec<tokio::task::JoinHandle<Result<(), StorageError>>> = Vec::new();
+    // Set once `shutdown` starts; rejects new connections instead of
+    // accepting them while we're draining.
+    let mut shutting_down = false;
+    // Documents that have been explicitly deleted. Kept around (rather than
+    // just absent from `documents`) so a sync message that was already in
+    // flight when the delete happened doesn't resurrect the document.
+    let mut tombstoned: HashSet<DocumentId> = HashSet::new();
+    // This run's identity in the ephemeral gossip protocol: unique enough,
+    // combined with a per-message counter, to dedupe re-broadcasts without
+    // needing a global message id scheme.
+    let session_id: u64 = rand::random();
+    let mut ephemeral_counter: u64 = 0;
+    let mut seen_ephemeral: HashSet<(RepoId, u64, u64)> = HashSet::new();
+    // Insertion order for `seen_ephemeral`, so the oldest id can be evicted
+    // once it grows past `MAX_SEEN_EPHEMERAL`.
+    let mut seen_ephemeral_order: std::collections::VecDeque<(RepoId, u64, u64)> =
+        std::collections::VecDeque::new();
+    let mut ephemeral_subscribers: HashMap<
+        DocumentId,
+        Vec<mpsc::UnboundedSender<(RepoId, Vec<u8>)>>,
+    > = HashMap::new();
+
+    loop {
+        let event = {
+            let receiver = receiver.clone();
+            match tokio::task::spawn_blocking(move || receiver.recv()).await {
+                Ok(Ok(event)) => event,
+                _ => break,
+            }
+        };
+        match event {
+            RepoEvent::Stop => break,
+            RepoEvent::NewDoc(id, doc) => {
+                if !tombstoned.contains(&id) {
+                    documents.insert(id, doc);
+                }
+            }
+            RepoEvent::DocClosed(id) => {
+                documents.remove(&id);
+                if let Some(waiting) = observers.remove(&id) {
+                    for mut resolver in waiting {
+                        resolver.resolve(Err(RepoError::DocumentNotFound(id.clone())));
+                    }
+                }
+                fail_heads_observers(&mut heads_observers, &id);
+                // Dropping the subscribers closes their `ephemeral()` streams
+                // instead of leaving them pending on a doc that's gone.
+                ephemeral_subscribers.remove(&id);
+            }
+            RepoEvent::DocChange(id) => {
+                if let Some(waiting) = observers.remove(&id) {
+                    for mut resolver in waiting {
+                        resolver.resolve(Ok(()));
+                    }
+                }
+                notify_heads_observers(&mut heads_observers, &documents, &id);
+                flush_doc_changes(&id, &documents, &connections, &storage, &mut pending_storage, None);
+            }
+            RepoEvent::AddChangeObserver(id, resolver) => {
+                observers.entry(id).or_default().push(resolver);
+            }
+            RepoEvent::AddHeadsObserver(id, before, observer) => {
+                heads_observers.entry(id).or_default().push((before, observer));
+            }
+            RepoEvent::RequestDoc(id, mut resolver) => {
+                resolver.resolve(Err(RepoError::DocumentNotFound(id)));
+            }
+            RepoEvent::ReceiveSync(from_addr, id, message) => {
+                if tombstoned.contains(&id) {
+                    // Drop rather than resurrect a deleted document.
+                    continue;
+                }
+                let Some(doc) = documents.get(&id) else {
+                    // We don't have this document to apply the changes onto;
+                    // fetching it first is out of scope until peers can also
+                    // announce/serve documents they don't yet have a handle
+                    // for locally.
+                    continue;
+                };
+                let before = doc.read().automerge.get_heads();
+                let applied = doc.write().automerge.load_incremental(&message).is_ok();
+                if !applied {
+                    // Malformed or incompatible sync payload; drop it rather
+                    // than taking down the connection over it.
+                    continue;
+                }
+                let after = doc.read().automerge.get_heads();
+                if after == before {
+                    // Nothing we didn't already have -- also the natural
+                    // point at which re-gossiping this message would stop,
+                    // since `flush_doc_changes` has nothing new to send.
+                    continue;
+                }
+                if let Some(waiting) = observers.remove(&id) {
+                    for mut resolver in waiting {
+                        resolver.resolve(Ok(()));
+                    }
+                }
+                notify_heads_observers(&mut heads_observers, &documents, &id);
+                flush_doc_changes(
+                    &id,
+                    &documents,
+                    &connections,
+                    &storage,
+                    &mut pending_storage,
+                    Some(&from_addr),
+                );
+            }
+            RepoEvent::DocDeleted(id) => {
+                if tombstoned.insert(id.clone()) {
+                    documents.remove(&id);
+                    if let Some(waiting) = observers.remove(&id) {
+                        for mut resolver in waiting {
+                            resolver.resolve(Err(RepoError::DocumentNotFound(id.clone())));
+                        }
+                    }
+                    fail_heads_observers(&mut heads_observers, &id);
+                    ephemeral_subscribers.remove(&id);
+                    let remove_storage = storage.clone();
+                    let remove_id = id.clone();
+                    pending_storage.push(tokio::spawn(async move {
+                        remove_storage.remove(remove_id).await
+                    }));
+                    let tombstone = WireMessage::Tombstone {
+                        document_id: id.clone(),
+                    };
+                    if let Ok(bytes) = bincode::serialize(&tombstone) {
+                        for outbox in connections.values() {
+                            let _ = outbox.send(bytes.clone());
+                        }
+                    }
+                }
+            }
+            RepoEvent::Ephemeral(document_id, payload) => {
+                ephemeral_counter += 1;
+                let message = EphemeralMessage {
+                    document_id,
+                    origin: local_repo_id.clone(),
+                    session_id,
+                    counter: ephemeral_counter,
+                    ttl: EPHEMERAL_TTL,
+                    payload,
+                };
+                remember_ephemeral(
+                    &mut seen_ephemeral,
+                    &mut seen_ephemeral_order,
+                    (message.origin.clone(), message.session_id, message.counter),
+                );
+                gossip_ephemeral(&connections, &message, None);
+            }
+            RepoEvent::EphemeralReceived(from_addr, message) => {
+                let key = (message.origin.clone(), message.session_id, message.counter);
+                if !remember_ephemeral(&mut seen_ephemeral, &mut seen_ephemeral_order, key) {
+                    // Already seen this one; drop it rather than looping it
+                    // around the gossip mesh forever.
+                    continue;
+                }
+                if let Some(subs) = ephemeral_subscribers.get(&message.document_id) {
+                    for sub in subs {
+                        let _ = sub.send((message.origin.clone(), message.payload.clone()));
+                    }
+                }
+                if message.ttl > 0 {
+                    let mut forwarded = message.clone();
+                    forwarded.ttl -= 1;
+                    gossip_ephemeral(&connections, &forwarded, Some(&from_addr));
+                }
+            }
+            RepoEvent::SubscribeEphemeral(document_id, subscriber) => {
+                ephemeral_subscribers
+                    .entry(document_id)
+                    .or_default()
+                    .push(subscriber);
+            }
+            RepoEvent::ConnectIo(addr, incoming, outgoing, _direction, mut resolver) => {
+                if shutting_down {
+                    resolver.resolve(Err(RepoError::Shutdown));
+                    continue;
+                }
+                let (outbox_tx, outbox_rx) = mpsc::unbounded_channel();
+                connections.insert(addr.clone(), outbox_tx);
+                let connection_sender = sender.clone();
+                let connection_addr = addr.clone();
+                connection_tasks.push(tokio::spawn(async move {
+                    run_connection(
+                        connection_addr,
+                        incoming,
+                        outgoing,
+                        outbox_rx,
+                        connection_sender.clone(),
+                    )
+                    .await;
+                    let _ = connection_sender.send(RepoEvent::ConnectionClosed(addr));
+                }));
+                resolver.resolve(Ok(()));
+            }
+            RepoEvent::ConnectionClosed(addr) => {
+                connections.remove(&addr);
+            }
+            RepoEvent::Shutdown(mut resolver) => {
+                shutting_down = true;
+                // Dropping every outbox sender doesn't discard what's
+                // already queued -- each connection task drains the rest of
+                // its buffer before `outbox.recv()` sees the channel close,
+                // so this just stops accepting anything further.
+                connections.clear();
+                for task in connection_tasks.drain(..) {
+                    let _ = task.await;
+                }
+                // Compact every still-open document down to its full current
+                // state, so a restart picks up from one snapshot instead of
+                // replaying whatever incremental chunks `flush_doc_changes`
+                // happened to have appended so far.
+                for (id, doc) in documents.iter() {
+                    let compact_storage = storage.clone();
+                    let compact_id = id.clone();
+                    let full_doc = doc.read().automerge.save();
+                    pending_storage.push(tokio::spawn(async move {
+                        compact_storage.compact(compact_id, full_doc).await
+                    }));
+                }
+                // Surface the first storage failure rather than silently
+                // dropping it -- `delete()` promises storage gets purged,
+                // so a backend that can't manage that should be heard.
+                let mut storage_failure = None;
+                for task in pending_storage.drain(..) {
+                    if let Ok(Err(e)) = task.await {
+                        storage_failure.get_or_insert(e);
+                    }
+                }
+                resolver.resolve(match storage_failure {
+                    Some(e) => Err(RepoError::Storage(e.to_string())),
+                    None => Ok(()),
+                });
+                break;
+            }
+        }
+    }
+
+    // Don't leave callers hanging if the repo goes away underneath them.
+    for (_, waiting) in observers {
+        for mut resolver in waiting {
+            resolver.resolve(Err(RepoError::Shutdown));
+        }
+    }
+}
+
+/// Serializes `message` once and fans it out to every connection except
+/// `exclude` (the one it just arrived on, if any).
+fn gossip_ephemeral(
+    connections: &HashMap<String, mpsc::UnboundedSender<Vec<u8>>>,
+    message: &EphemeralMessage,
+    exclude: Option<&str>,
+) {
+    let Ok(bytes) = bincode::serialize(&WireMessage::Ephemeral(message.clone())) else {
+        return;
+    };
+    for (addr, outbox) in connections {
+        if Some(addr.as_str()) == exclude {
+            continue;
+        }
+        let _ = outbox.send(bytes.clone());
+    }
+}
+
+/// Records `key` as seen for ephemeral-message dedup, evicting the oldest
+/// remembered id once `seen_ephemeral` grows past `MAX_SEEN_EPHEMERAL`.
+/// Returns whether `key` was newly inserted (mirroring `HashSet::insert`).
+fn remember_ephemeral(
+    seen_ephemeral: &mut HashSet<(RepoId, u64, u64)>,
+    seen_ephemeral_order: &mut std::collections::VecDeque<(RepoId, u64, u64)>,
+    key: (RepoId, u64, u64),
+) -> bool {
+    if !seen_ephemeral.insert(key.clone()) {
+        return false;
+    }
+    seen_ephemeral_order.push_back(key);
+    if seen_ephemeral_order.len() > MAX_SEEN_EPHEMERAL {
+        if let Some(oldest) = seen_ephemeral_order.pop_front() {
+            seen_ephemeral.remove(&oldest);
+        }
+    }
+    true
+}
+
+/// Fails every heads observer waiting on `id`, because the document it was
+/// watching is gone (closed or deleted) and will never emit another
+/// `DocChange` for this to react to. A `Once` observer resolves with
+/// `DocumentNotFound`, matching `changed()`'s handling of the same cases;
+/// dropping a `Batched` sender closes its stream instead of leaving it
+/// pending forever.
+fn fail_heads_observers(
+    heads_observers: &mut HashMap<DocumentId, Vec<(Vec<ChangeHash>, HeadsObserver)>>,
+    id: &DocumentId,
+) {
+    let Some(waiting) = heads_observers.remove(id) else {
+        return;
+    };
+    for (_, observer) in waiting {
+        if let HeadsObserver::Once(mut resolver) = observer {
+            resolver.resolve(Err(RepoError::DocumentNotFound(id.clone())));
+        }
+        // `HeadsObserver::Batched` just drops here, closing the stream.
+    }
+}
+
+/// Resolves every heads observer waiting on `id` whose baseline heads have
+/// been passed, same logic the `DocChange` and `ReceiveSync` handlers both
+/// need -- a document advancing is a document advancing, whether the change
+/// originated locally or came in over a sync message.
+fn notify_heads_observers(
+    heads_observers: &mut HashMap<DocumentId, Vec<(Vec<ChangeHash>, HeadsObserver)>>,
+    documents: &HashMap<DocumentId, Arc<RwLock<SharedDocument>>>,
+    id: &DocumentId,
+) {
+    let Some(waiting) = heads_observers.remove(id) else {
+        return;
+    };
+    let after = documents.get(id).map(|doc| doc.read().automerge.get_heads());
+    let mut still_waiting = Vec::new();
+    for (before, observer) in waiting {
+        let Some(after) = after.clone() else {
+            still_waiting.push((before, observer));
+            continue;
+        };
+        if after == before {
+            still_waiting.push((before, observer));
+            continue;
+        }
+        let notification = ChangeNotification {
+            patches: diff_heads(documents, id, &before, &after),
+            before,
+            after: after.clone(),
+        };
+        match observer {
+            HeadsObserver::Once(mut resolver) => {
+                resolver.resolve(Ok(notification));
+            }
+            HeadsObserver::Batched(sender) => {
+                if sender.send(Ok(notification)).is_ok() {
+                    still_waiting.push((after, HeadsObserver::Batched(sender)));
+                }
+            }
+        }
+    }
+    if !still_waiting.is_empty() {
+        heads_observers.insert(id.clone(), still_waiting);
+    }
+}
+
+/// Persists and gossips whatever `id` has changed since the last call to
+/// `save_incremental` on it: spawns a `storage.append` write and, if the
+/// document has any connections, forwards the same incremental bytes as a
+/// `WireMessage::Sync`, skipping `exclude` (the connection the change itself
+/// arrived from, if any, so it doesn't get echoed straight back).
+/// `save_incremental` returning nothing new is also what stops a sync
+/// message from re-gossiping forever once every peer has converged on it.
+fn flush_doc_changes(
+    id: &DocumentId,
+    documents: &HashMap<DocumentId, Arc<RwLock<SharedDocument>>>,
+    connections: &HashMap<String, mpsc::UnboundedSender<Vec<u8>>>,
+    storage: &Arc<dyn Storage>,
+    pending_storage: &mut Vec<tokio::task::JoinHandle<Result<(), StorageError>>>,
+    exclude: Option<&str>,
+) {
+    let Some(doc) = documents.get(id) else {
+        return;
+    };
+    let incremental = doc.write().automerge.save_incremental();
+    if incremental.is_empty() {
+        return;
+    }
+    let append_storage = storage.clone();
+    let append_id = id.clone();
+    let append_bytes = incremental.clone();
+    pending_storage.push(tokio::spawn(async move {
+        append_storage.append(append_id, append_bytes).await
+    }));
+    let wire = WireMessage::Sync {
+        document_id: id.clone(),
+        message: incremental,
+    };
+    if let Ok(bytes) = bincode::serialize(&wire) {
+        for (addr, outbox) in connections {
+            if Some(addr.as_str()) == exclude {
+                continue;
+            }
+            let _ = outbox.send(bytes.clone());
+        }
+    }
+}
+
+fn diff_heads(
+    documents: &HashMap<DocumentId, Arc<RwLock<SharedDocument>>>,
+    id: &DocumentId,
+    before: &[ChangeHash],
+    after: &[ChangeHash],
+) -> Vec<Patch> {
+    documents
+        .get(id)
+        .map(|doc| doc.read().automerge.diff(before, after))
+        .unwrap_or_default()
+}
+
+fn pin_peer_repo_id(
+    peer_certificates: Option<&[rustls::Certificate]>,
+    expected: &RepoId,
+) -> Result<(), RepoError> {
+    let leaf = peer_certificates
+        .and_then(|certs| certs.first())
+        .ok_or_else(|| {
+            NetworkError::HandshakeFailed("peer presented no certificate".to_string())
+        })?;
+    crate::tls::verify_peer_repo_id(leaf, expected)?;
+    Ok(())
+}
+
+/// Owns one connection: decodes incoming [`WireMessage`]s and forwards them
+/// to the run loop as [`RepoEvent`]s, and serializes anything the run loop
+/// pushes onto `outbox` back out over `outgoing`.
+async fn run_connection(
+    addr: String,
+    mut incoming: FrameStream,
+    mut outgoing: FrameSink,
+    mut outbox: mpsc::UnboundedReceiver<Vec<u8>>,
+    sender: Sender<RepoEvent>,
+) {
+    loop {
+        tokio::select! {
+            frame = incoming.next() => {
+                let Some(frame) = frame else { break };
+                match bincode::deserialize::<WireMessage>(&frame) {
+                    Ok(WireMessage::Sync { document_id, message }) => {
+                        if sender
+                            .send(RepoEvent::ReceiveSync(addr.clone(), document_id, message))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Ok(WireMessage::Tombstone { document_id }) => {
+                        if sender.send(RepoEvent::DocDeleted(document_id)).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(WireMessage::Ephemeral(message)) => {
+                        if sender
+                            .send(RepoEvent::EphemeralReceived(addr.clone(), message))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+            frame = outbox.recv() => {
+                match frame {
+                    Some(bytes) => {
+                        if outgoing.send(bytes).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+/// A cloneable handle to a running [`Repo`].
+#[derive(Clone)]
+pub struct RepoHandle {
+    pub(crate) sender: Sender<RepoEvent>,
+    local_repo_id: RepoId,
+    tls_config: Option<TlsConfig>,
+}
+
+impl RepoHandle {
+    pub fn local_repo_id(&self) -> RepoId {
+        self.local_repo_id.clone()
+    }
+
+    /// Creates a brand-new, empty document and returns a handle to it.
+    pub fn new_document(&self) -> DocHandle {
+        let document_id = DocumentId::new();
+        let shared_document = Arc::new(RwLock::new(SharedDocument {
+            automerge: Automerge::new(),
+        }));
+        self.sender
+            .send(RepoEvent::NewDoc(document_id.clone(), shared_document.clone()))
+            .expect("repo has shut down");
+        DocHandle::new(
+            self.sender.clone(),
+            document_id,
+            shared_document,
+            Arc::new(AtomicUsize::new(0)),
+            self.local_repo_id.clone(),
+        )
+    }
+
+    /// Requests a document this repo doesn't yet have, by id. The run loop
+    /// does not fetch documents from peers: this always resolves to
+    /// `RepoError::DocumentNotFound`.
+    pub async fn request_document(&self, id: DocumentId) -> Result<DocHandle, RepoError> {
+        let (fut, resolver) = new_repo_future_with_resolver();
+        self.sender
+            .send(RepoEvent::RequestDoc(id, resolver))
+            .map_err(|_| RepoError::Shutdown)?;
+        fut.await
+    }
+
+    /// Hands a plain, already-connected TCP stream to the repo's sync
+    /// engine.
+    pub async fn connect_tokio_io<A, IO>(
+        &self,
+        addr: A,
+        stream: IO,
+        direction: ConnDirection,
+    ) -> Result<(), RepoError>
+    where
+        A: ToString,
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (incoming, outgoing) = framed_from_io(Box::new(stream));
+        self.connect_io(addr.to_string(), incoming, outgoing, direction)
+            .await
+    }
+
+    /// Like [`Self::connect_tokio_io`], but wraps `stream` in mutual TLS
+    /// using the repo's configured [`TlsConfig`] before handing it to the
+    /// same generic connect path. Requires the repo to have been built
+    /// with [`Repo::new_with_tls`].
+    /// `expected_repo_id`, if given, pins the peer's certificate CN to that
+    /// repo id so a node can't sync under another node's identity even
+    /// though it holds a CA-signed cert.
+    pub async fn connect_tokio_io_tls<A, IO>(
+        &self,
+        addr: A,
+        stream: IO,
+        direction: ConnDirection,
+        expected_repo_id: Option<RepoId>,
+    ) -> Result<(), RepoError>
+    where
+        A: ToString,
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let tls_config = self.tls_config.as_ref().ok_or_else(|| {
+            RepoError::Network(NetworkError::HandshakeFailed(
+                "this repo has no TlsConfig configured".to_string(),
+            ))
+        })?;
+        let io: Box<dyn AsyncIo> = match direction {
+            ConnDirection::Outgoing => {
+                let connector = TlsConnector::from(Arc::new(tls_config.client_config()?));
+                // `client_config()` installs `NoHostnameVerifier`, which
+                // ignores this value entirely and checks the presented
+                // certificate against its own claimed name instead -- so
+                // this is only here to satisfy `connect`'s signature. The
+                // peer's claimed identity is pinned against its
+                // certificate's CN afterwards, via `pin_peer_repo_id`.
+                let server_name = rustls::ServerName::try_from("spanreed-peer")
+                    .expect("static server name is always valid");
+                let tls_stream = connector
+                    .connect(server_name, stream)
+                    .await
+                    .map_err(|e| NetworkError::HandshakeFailed(e.to_string()))?;
+                if let Some(expected) = &expected_repo_id {
+                    pin_peer_repo_id(tls_stream.get_ref().1.peer_certificates(), expected)?;
+                }
+                Box::new(tls_stream)
+            }
+            ConnDirection::Incoming => {
+                let acceptor = TlsAcceptor::from(Arc::new(tls_config.server_config()?));
+                let tls_stream = acceptor
+                    .accept(stream)
+                    .await
+                    .map_err(|e| NetworkError::HandshakeFailed(e.to_string()))?;
+                if let Some(expected) = &expected_repo_id {
+                    pin_peer_repo_id(tls_stream.get_ref().1.peer_certificates(), expected)?;
+                }
+                Box::new(tls_stream)
+            }
+        };
+        let (incoming, outgoing) = framed_from_io(io);
+        self.connect_io(addr.to_string(), incoming, outgoing, direction)
+            .await
+    }
+
+    /// Dials `url` and carries the sync protocol over a binary WebSocket,
+    /// so spanreed can reach peers behind load balancers or HTTP(S)-only
+    /// proxies. `direction` must be [`ConnDirection::Outgoing`] -- the
+    /// accepting side goes through [`Self::accept_websocket`] instead,
+    /// since it starts from an already-accepted connection rather than a
+    /// URL to dial.
+    pub async fn connect_websocket(
+        &self,
+        url: &str,
+        direction: ConnDirection,
+    ) -> Result<(), RepoError> {
+        if direction != ConnDirection::Outgoing {
+            return Err(RepoError::Network(NetworkError::HandshakeFailed(
+                "connect_websocket only dials out; use accept_websocket for inbound connections"
+                    .to_string(),
+            )));
+        }
+        let (ws_stream, _response) = tokio_tungstenite::connect_async(url)
+            .await
+            .map_err(|e| NetworkError::HandshakeFailed(e.to_string()))?;
+        let (incoming, outgoing) = framed_from_websocket(ws_stream);
+        self.connect_io(url.to_string(), incoming, outgoing, direction)
+            .await
+    }
+
+    /// Completes the WebSocket handshake on an already-accepted connection
+    /// (e.g. from an HTTP server's upgrade) and carries the sync protocol
+    /// over it, same as [`Self::connect_websocket`] but for the inbound
+    /// side.
+    pub async fn accept_websocket<A, IO>(&self, addr: A, stream: IO) -> Result<(), RepoError>
+    where
+        A: ToString,
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let ws_stream = tokio_tungstenite::accept_async(stream)
+            .await
+            .map_err(|e| NetworkError::HandshakeFailed(e.to_string()))?;
+        let (incoming, outgoing) = framed_from_websocket(ws_stream);
+        self.connect_io(addr.to_string(), incoming, outgoing, ConnDirection::Incoming)
+            .await
+    }
+
+    pub(crate) async fn connect_io(
+        &self,
+        addr: String,
+        incoming: FrameStream,
+        outgoing: FrameSink,
+        direction: ConnDirection,
+    ) -> Result<(), RepoError> {
+        let (fut, resolver) = new_repo_future_with_resolver();
+        self.sender
+            .send(RepoEvent::ConnectIo(addr, incoming, outgoing, direction, resolver))
+            .map_err(|_| RepoError::Shutdown)?;
+        fut.await
+    }
+
+    /// Tears the repo down immediately, without waiting for in-flight
+    /// storage writes or sync traffic to flush. Prefer [`Self::shutdown`]
+    /// unless an abrupt stop is actually what's wanted.
+    pub fn stop(&self) -> Result<(), RepoError> {
+        self.sender
+            .send(RepoEvent::Stop)
+            .map_err(|_| RepoError::Shutdown)
+    }
+
+    /// Tears the repo down gracefully: stops accepting new connections,
+    /// lets already-queued outbound sync frames finish writing to peers,
+    /// waits for in-flight storage writes to land, and only then resolves.
+    pub fn shutdown(&self) -> RepoFuture<Result<(), RepoError>> {
+        let (fut, resolver) = new_repo_future_with_resolver();
+        self.sender
+            .send(RepoEvent::Shutdown(resolver))
+            .expect("repo has shut down");
+        fut
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoopStorage;
+    impl Storage for NoopStorage {}
+
+    #[tokio::test]
+    async fn changed_with_heads_ignores_spurious_wakeups() {
+        let repo = Repo::new(None, Box::new(NoopStorage));
+        let handle = repo.run();
+        let doc = handle.new_document();
+        let mut fut = doc.changed_with_heads();
+
+        // A `DocChange` can fire without the document's heads moving (e.g.
+        // a `with_doc_mut` call that made no edit); that must not
+        // resolve a heads-aware observer the way it would a plain `changed`.
+        handle
+            .sender
+            .send(RepoEvent::DocChange(doc.document_id()))
+            .unwrap();
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(50), &mut fut)
+            .await
+            .is_err());
+
+        // An actual edit does resolve it, carrying the heads that moved.
+        let before = doc.with_doc(|d| d.get_heads());
+        doc.with_doc_mut(|d| {
+            let mut tx = d.transaction();
+            tx.put(automerge::ROOT, "k", "v").unwrap();
+            tx.commit();
+        });
+        let notification = fut.await.expect("repo is still running");
+        assert_eq!(notification.before, before);
+        assert_ne!(notification.after, before);
+    }
+
+    #[tokio::test]
+    async fn heads_observer_is_failed_when_document_is_deleted() {
+        let repo = Repo::new(None, Box::new(NoopStorage));
+        let handle = repo.run();
+        let doc = handle.new_document();
+        let fut = doc.changed_with_heads();
+        doc.delete();
+        assert!(matches!(
+            fut.await,
+            Err(RepoError::DocumentNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn ephemeral_stream_closes_when_document_is_deleted() {
+        let repo = Repo::new(None, Box::new(NoopStorage));
+        let handle = repo.run();
+        let doc = handle.new_document();
+        let mut stream = Box::pin(doc.ephemeral());
+        doc.delete();
+        assert_eq!(stream.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn ephemeral_stream_closes_when_last_handle_drops() {
+        let repo = Repo::new(None, Box::new(NoopStorage));
+        let handle = repo.run();
+        let doc = handle.new_document();
+        let mut stream = Box::pin(doc.ephemeral());
+        drop(doc);
+        assert_eq!(stream.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn delete_is_idempotent() {
+        let repo = Repo::new(None, Box::new(NoopStorage));
+        let handle = repo.run();
+        let doc = handle.new_document();
+        let doc_clone = doc.clone();
+        doc.delete();
+        // A second delete, from a clone held elsewhere, must not panic or
+        // re-run the purge/tombstone side effects.
+        doc_clone.delete();
+        assert!(handle.shutdown().await.is_ok());
+    }
+
+    struct FailingRemoveStorage;
+    #[async_trait::async_trait]
+    impl Storage for FailingRemoveStorage {
+        async fn remove(&self, _id: DocumentId) -> Result<(), StorageError> {
+            Err(StorageError::Other("disk is full".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_surfaces_a_failed_storage_purge() {
+        let repo = Repo::new(None, Box::new(FailingRemoveStorage));
+        let handle = repo.run();
+        let doc = handle.new_document();
+        doc.delete();
+        assert!(matches!(
+            handle.shutdown().await,
+            Err(RepoError::Storage(_))
+        ));
+    }
+
+    #[test]
+    fn try_with_doc_mut_returns_none_under_contention() {
+        let repo = Repo::new(None, Box::new(NoopStorage));
+        let handle = repo.run();
+        let doc = handle.new_document();
+        let doc_for_writer = doc.clone();
+        let (holding_tx, holding_rx) = std::sync::mpsc::channel::<()>();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+
+        let writer = std::thread::spawn(move || {
+            doc_for_writer.with_doc_mut(|_automerge| {
+                holding_tx.send(()).unwrap();
+                // Hold the write lock until the test has observed the
+                // contention, rather than racing it.
+                let _ = release_rx.recv();
+            });
+        });
+
+        holding_rx.recv().unwrap();
+        assert!(doc.try_with_doc(|_| ()).is_none());
+        assert!(doc.try_with_doc_mut(|_| ()).is_none());
+        release_tx.send(()).unwrap();
+        writer.join().unwrap();
+
+        // Once released, both succeed again.
+        assert!(doc.try_with_doc(|_| ()).is_some());
+        assert!(doc.try_with_doc_mut(|_| ()).is_some());
+    }
+
+    struct SlowRemoveStorage;
+    #[async_trait::async_trait]
+    impl Storage for SlowRemoveStorage {
+        async fn remove(&self, _id: DocumentId) -> Result<(), StorageError> {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_an_in_flight_storage_write_before_resolving() {
+        let repo = Repo::new(None, Box::new(SlowRemoveStorage));
+        let handle = repo.run();
+        let doc = handle.new_document();
+        doc.delete();
+
+        let started = std::time::Instant::now();
+        assert!(handle.shutdown().await.is_ok());
+        assert!(started.elapsed() >= std::time::Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn shutdown_rejects_new_connections() {
+        let repo = Repo::new(None, Box::new(NoopStorage));
+        let handle = repo.run();
+        assert!(handle.shutdown().await.is_ok());
+
+        let (client, _server) = tokio::io::duplex(1024);
+        let result = handle
+            .connect_tokio_io("late-comer", client, ConnDirection::Outgoing)
+            .await;
+        assert!(result.is_err());
+    }
+
+    /// Generates a throwaway leaf cert for `common_name`, signed by `ca` (from
+    /// `generate_ca`), standing in for real operator-provisioned certs so
+    /// these tests don't need checked-in fixtures.
+    fn generate_leaf_cert(ca: &rcgen::Certificate, common_name: &str) -> TlsConfig {
+        let mut params = rcgen::CertificateParams::new(vec![common_name.to_string()]);
+        let mut dn = rcgen::DistinguishedName::new();
+        dn.push(rcgen::DnType::CommonName, common_name);
+        params.distinguished_name = dn;
+        let leaf = rcgen::Certificate::from_params(params).unwrap();
+        TlsConfig {
+            ca_cert: ca.serialize_pem().unwrap().into_bytes(),
+            node_cert: leaf.serialize_pem_with_signer(ca).unwrap().into_bytes(),
+            node_key: leaf.serialize_private_key_pem().into_bytes(),
+        }
+    }
+
+    fn generate_ca() -> rcgen::Certificate {
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_handshake_succeeds_with_pinned_repo_id() {
+        let ca = generate_ca();
+        let server_tls = generate_leaf_cert(&ca, "node-b");
+        let client_tls = generate_leaf_cert(&ca, "node-a");
+
+        let server = Repo::new_with_tls(
+            Some(RepoId("node-b".to_string())),
+            Box::new(NoopStorage),
+            server_tls,
+        )
+        .run();
+        let client = Repo::new_with_tls(
+            Some(RepoId("node-a".to_string())),
+            Box::new(NoopStorage),
+            client_tls,
+        )
+        .run();
+
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let server_fut = server.connect_tokio_io_tls(
+            "client",
+            server_io,
+            ConnDirection::Incoming,
+            Some(RepoId("node-a".to_string())),
+        );
+        let client_fut = client.connect_tokio_io_tls(
+            "server",
+            client_io,
+            ConnDirection::Outgoing,
+            Some(RepoId("node-b".to_string())),
+        );
+        let (server_result, client_result) = tokio::join!(server_fut, client_fut);
+        server_result.expect("server side of a legitimately pinned handshake should succeed");
+        client_result.expect("client side of a legitimately pinned handshake should succeed");
+    }
+
+    #[tokio::test]
+    async fn websocket_connection_syncs_a_document_end_to_end() {
+        let repo_a = Repo::new(None, Box::new(NoopStorage)).run();
+        let repo_b = Repo::new(None, Box::new(NoopStorage)).run();
+
+        let doc_a = repo_a.new_document();
+        let document_id = doc_a.document_id();
+
+        // Give repo_b an empty copy of the same document up front, standing
+        // in for a real announce/fetch handshake (out of scope here) so
+        // this test can focus on whether a `Sync` frame actually survives
+        // the WebSocket framing end to end.
+        let shared_b = Arc::new(RwLock::new(SharedDocument {
+            automerge: Automerge::new(),
+        }));
+        repo_b
+            .sender
+            .send(RepoEvent::NewDoc(document_id.clone(), shared_b.clone()))
+            .unwrap();
+        let doc_b = DocHandle::new(
+            repo_b.sender.clone(),
+            document_id,
+            shared_b,
+            Arc::new(AtomicUsize::new(0)),
+            repo_b.local_repo_id(),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_repo = repo_b.clone();
+        tokio::spawn(async move {
+            let (stream, peer_addr) = listener.accept().await.unwrap();
+            server_repo
+                .accept_websocket(peer_addr.to_string(), stream)
+                .await
+                .unwrap();
+        });
+
+        repo_a
+            .connect_websocket(&format!("ws://{addr}"), ConnDirection::Outgoing)
+            .await
+            .unwrap();
+
+        let fut = doc_b.changed_with_heads();
+        doc_a.with_doc_mut(|d| {
+            let mut tx = d.transaction();
+            tx.put(automerge::ROOT, "k", "v").unwrap();
+            tx.commit();
+        });
+        let notification = tokio::time::timeout(std::time::Duration::from_secs(5), fut)
+            .await
+            .expect("sync frame never arrived over the websocket")
+            .expect("repo is still running");
+        assert_ne!(notification.after, notification.before);
+        assert!(doc_b
+            .with_doc(|d| d.get(automerge::ROOT, "k").unwrap())
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_handshake_rejects_a_mismatched_pinned_repo_id() {
+        let ca = generate_ca();
+        let server_tls = generate_leaf_cert(&ca, "node-b");
+        let client_tls = generate_leaf_cert(&ca, "node-a");
+
+        let server = Repo::new_with_tls(
+            Some(RepoId("node-b".to_string())),
+            Box::new(NoopStorage),
+            server_tls,
+        )
+        .run();
+        let client = Repo::new_with_tls(
+            Some(RepoId("node-a".to_string())),
+            Box::new(NoopStorage),
+            client_tls,
+        )
+        .run();
+
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let server_fut = server.connect_tokio_io_tls(
+            "client",
+            server_io,
+            ConnDirection::Incoming,
+            // Expects the wrong peer -- the handshake itself (chain/expiry)
+            // is still fine, it's `pin_peer_repo_id` that must reject this.
+            Some(RepoId("someone-else".to_string())),
+        );
+        let client_fut = client.connect_tokio_io_tls(
+            "server",
+            client_io,
+            ConnDirection::Outgoing,
+            Some(RepoId("node-b".to_string())),
+        );
+        let (server_result, _client_result) = tokio::join!(server_fut, client_fut);
+        assert!(matches!(
+            server_result,
+            Err(RepoError::Network(NetworkError::PeerIdMismatch { .. }))
+        ));
+    }
+}
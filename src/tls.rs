@@ -0,0 +1,144 @@
+use crate::interfaces::{NetworkError, RepoId};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName};
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Certificate material for mutual TLS between repo nodes: a CA that both
+/// sides trust, and this node's own certificate/key signed by it.
+#[derive(Clone)]
+pub struct TlsConfig {
+    pub ca_cert: Vec<u8>,
+    pub node_cert: Vec<u8>,
+    pub node_key: Vec<u8>,
+}
+
+impl TlsConfig {
+    fn root_store(&self) -> Result<RootCertStore, NetworkError> {
+        let mut store = RootCertStore::empty();
+        let certs = rustls_pemfile::certs(&mut Cursor::new(&self.ca_cert))
+            .map_err(|e| NetworkError::HandshakeFailed(format!("invalid ca_cert: {e}")))?;
+        for cert in certs {
+            store
+                .add(&Certificate(cert))
+                .map_err(|e| NetworkError::HandshakeFailed(format!("invalid ca_cert: {e}")))?;
+        }
+        Ok(store)
+    }
+
+    fn node_cert_chain(&self) -> Result<Vec<Certificate>, NetworkError> {
+        rustls_pemfile::certs(&mut Cursor::new(&self.node_cert))
+            .map(|certs| certs.into_iter().map(Certificate).collect())
+            .map_err(|e| NetworkError::HandshakeFailed(format!("invalid node_cert: {e}")))
+    }
+
+    fn node_private_key(&self) -> Result<PrivateKey, NetworkError> {
+        let mut keys = rustls_pemfile::pkcs8_private_keys(&mut Cursor::new(&self.node_key))
+            .map_err(|e| NetworkError::HandshakeFailed(format!("invalid node_key: {e}")))?;
+        keys.pop()
+            .map(PrivateKey)
+            .ok_or_else(|| NetworkError::HandshakeFailed("node_key has no key in it".to_string()))
+    }
+
+    /// Config for the side dialing out: trust the CA, present our cert.
+    ///
+    /// Peers here aren't addressed by DNS name -- there's no hostname to
+    /// check the presented certificate against until well after the
+    /// handshake, once we know which `RepoId` we actually reached. So this
+    /// uses `NoHostnameVerifier` to still validate the chain and expiry
+    /// against our CA, and leaves identity enforcement to the
+    /// `pin_peer_repo_id` check that runs once the handshake completes.
+    pub(crate) fn client_config(&self) -> Result<ClientConfig, NetworkError> {
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(NoHostnameVerifier::new(
+                self.root_store()?,
+            )))
+            .with_client_auth_cert(self.node_cert_chain()?, self.node_private_key()?)
+            .map_err(|e| NetworkError::HandshakeFailed(e.to_string()))
+    }
+
+    /// Config for the side accepting a connection: require the peer to
+    /// present a certificate signed by the CA.
+    pub(crate) fn server_config(&self) -> Result<ServerConfig, NetworkError> {
+        let verifier = rustls::server::AllowAnyAuthenticatedClient::new(self.root_store()?);
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(Arc::new(verifier))
+            .with_single_cert(self.node_cert_chain()?, self.node_private_key()?)
+            .map_err(|e| NetworkError::HandshakeFailed(e.to_string()))
+    }
+}
+
+/// Validates a peer's certificate chain and expiry against our trusted CA,
+/// but skips the usual hostname check: peers here are identified by
+/// `RepoId`, not DNS name, and which `RepoId` we actually reached isn't
+/// known until the handshake is already underway. Instead of checking the
+/// certificate's name against whatever address we dialed, this checks it
+/// against the name the certificate itself claims, which trivially passes
+/// and leaves the chain/expiry validation otherwise unchanged. Real
+/// identity enforcement happens afterwards, via `verify_peer_repo_id`.
+struct NoHostnameVerifier(WebPkiVerifier);
+
+impl NoHostnameVerifier {
+    fn new(roots: RootCertStore) -> Self {
+        NoHostnameVerifier(WebPkiVerifier::new(roots, None))
+    }
+}
+
+impl ServerCertVerifier for NoHostnameVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let (_, parsed) = x509_parser::parse_x509_certificate(&end_entity.0)
+            .map_err(|e| rustls::Error::General(format!("malformed peer certificate: {e}")))?;
+        let common_name = parsed
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .ok_or_else(|| rustls::Error::General("peer certificate has no CN".to_string()))?;
+        let self_asserted_name = ServerName::try_from(common_name).map_err(|_| {
+            rustls::Error::General("peer certificate CN is not a valid server name".to_string())
+        })?;
+        self.0.verify_server_cert(
+            end_entity,
+            intermediates,
+            &self_asserted_name,
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
+
+/// Confirms the peer's certificate was issued to the `RepoId` it claims to
+/// be, so a node can't sync under another node's identity just because it
+/// holds a CA-signed cert.
+pub(crate) fn verify_peer_repo_id(
+    cert: &Certificate,
+    claimed: &RepoId,
+) -> Result<(), NetworkError> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0)
+        .map_err(|e| NetworkError::HandshakeFailed(format!("malformed peer certificate: {e}")))?;
+    let common_name = parsed
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .ok_or_else(|| NetworkError::HandshakeFailed("peer certificate has no CN".to_string()))?;
+    if common_name != claimed.0 {
+        return Err(NetworkError::PeerIdMismatch {
+            expected: claimed.clone(),
+            got: RepoId(common_name.to_string()),
+        });
+    }
+    Ok(())
+}